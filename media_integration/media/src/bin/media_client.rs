@@ -6,7 +6,9 @@
 //! dummy data. (Sending a 240-byte payload every 20ms represents a 96 kbps
 //! stream.) When <TIMEOUT> time has elapsed, send a timeout packet where the
 //! sequence number is the max u32 integer. On receiving a NACK, retransmit
-//! the missing packet that was identified in the NACK.
+//! the missing packet that was identified in the NACK. To exercise loss
+//! recovery without an emulated network, <LOSS> drops each outgoing data
+//! packet with the given probability before it reaches the socket.
 //!
 //! When using a quACK, immediately retransmit missing packets from the quACK
 //! i.e. a packet is missing after 3 later packets have been received. If the
@@ -61,11 +63,23 @@ struct Cli {
     /// QuACK threshold.
     #[arg(long, default_value_t = 8)]
     threshold: usize,
+    /// Probability of dropping each data packet before it is sent, to
+    /// simulate loss e.g., over loopback. Timeout packets are never dropped.
+    #[arg(long, default_value_t = 0.0)]
+    loss: f64,
 }
 
 /// NACKs just have 4 bytes for the sequence number.
 const NACK_BUFFER_SIZE: usize = 4;
 
+/// Sequence number of the timeout message. Data packets use sequence numbers
+/// `1..TIMEOUT_SEQNO`, so the sentinel is never treated as data.
+const TIMEOUT_SEQNO: u32 = u32::MAX;
+
+/// Modulus of the u32 power sum quACK, the largest prime less than 2^32.
+/// Identifiers are elements of this field.
+const MODULUS: u32 = 4_294_967_291;
+
 /// The sidekick sniffs at a certain offset in QUIC packets such that those
 /// bytes are randomly-encrypted. I don't want to edit the sidekick code
 /// currently so I will set the sequence numbers here in the same offset.
@@ -100,9 +114,10 @@ impl PacketSender {
         })
     }
 
-    /// Send a packet with this sequence number to the server.
+    /// Send a packet with this sequence number to the server. The quACK
+    /// identifier is a nonzero field element drawn from `1..MODULUS`.
     async fn send(&mut self, seqno: u32) -> io::Result<()> {
-        let id: u32 = rand::thread_rng().gen();
+        let id: u32 = rand::thread_rng().gen_range(1..MODULUS);
 
         // Add the new packet to the buffer and send the packet.
         // (may be some harmless reordering here)
@@ -116,14 +131,21 @@ impl PacketSender {
 
 /// Listen to the mpsc channel and actually send packets on the UDP socket.
 /// Receives sequence numbers and random identifiers and fills the packets.
+/// Drops data packets with probability `loss` to simulate a lossy link.
 async fn send_data(
     sock: Arc<UdpSocket>,
     bytes: usize,
+    loss: f64,
     mut rx: mpsc::Receiver<(u32, u32)>,
 ) -> io::Result<()> {
     let mut payload = vec![0xFF; bytes];
     tokio::spawn(async move {
         while let Some((seqno, id)) = rx.recv().await {
+            if seqno != TIMEOUT_SEQNO && rand::thread_rng().gen_bool(loss) {
+                trace!("simulate loss {}", seqno);
+                continue;
+            }
+
             // Set the sequence number in the first 4 bytes.
             let seqno_bytes = seqno.to_be_bytes();
             payload[0] = seqno_bytes[0];
@@ -200,6 +222,36 @@ fn listen_for_quacks_strawman_c(mut _sender: PacketSender, _quack_port: u16) {
     tokio::spawn(async move { unimplemented!() });
 }
 
+/// Subtract the sidekick's quACK of received packets from our quACK of sent
+/// packets, and decode the difference against the log of sent packets.
+/// Returns the (sequence number, identifier) pairs to retransmit, up to the
+/// last value the sidekick received. The difference must not exceed the
+/// quACK threshold.
+fn nack_set(
+    my_quack: &PowerSumQuackU32,
+    quack: PowerSumQuackU32,
+    seqno_ids: &[(u32, u32)],
+) -> Vec<(u32, u32)> {
+    let mut diff_quack = my_quack.clone();
+    diff_quack.sub_assign(quack);
+    if diff_quack.count() == 0 {
+        return vec![];
+    }
+
+    // Identify the missing sequence numbers up to the last value received.
+    let coeffs = diff_quack.to_coeffs();
+    let mut missing_seqno_ids = Vec::new();
+    for &(seqno, id) in seqno_ids.iter() {
+        if Some(id) == diff_quack.last_value() {
+            break;
+        }
+        if arithmetic::eval(&coeffs, id).value() == 0 {
+            missing_seqno_ids.push((seqno, id));
+        }
+    }
+    missing_seqno_ids
+}
+
 /// Spawn a thread that listens for sidekick quACKs using the power sum quACK
 /// and retransmit packets when determined missing.
 fn listen_for_quacks_power_sum(
@@ -283,8 +335,8 @@ fn listen_for_quacks_power_sum(
                 last_quack_reset = None;
             }
 
-            // If the number of missing packets exceeds the threshold, reset
-            // the quack. If no packets are missing, continue on.
+            // Decode the difference between what we sent and what the
+            // sidekick received.
             trace!(
                 "quack counts {} - {} (last values {:?} {:?})",
                 my_quack.count(),
@@ -292,25 +344,7 @@ fn listen_for_quacks_power_sum(
                 my_quack.last_value(),
                 quack.last_value()
             );
-            let mut diff_quack = my_quack.clone();
-            diff_quack.sub_assign(quack);
-            if diff_quack.count() == 0 {
-                seqno_ids.drain(..(last_index_inserted + 1));
-                continue;
-            }
-
-            // Identify the missing sequence numbers up to the last value
-            // received.
-            let coeffs = diff_quack.to_coeffs();
-            let mut missing_seqno_ids = Vec::new();
-            for &(seqno, id) in seqno_ids.iter() {
-                if Some(id) == diff_quack.last_value() {
-                    break;
-                }
-                if arithmetic::eval(&coeffs, id).value() == 0 {
-                    missing_seqno_ids.push((seqno, id));
-                }
-            }
+            let missing_seqno_ids = nack_set(&my_quack, quack, &seqno_ids);
 
             // Retransmit any missing packets.
             seqno_ids.drain(..(last_index_inserted + 1));
@@ -336,7 +370,7 @@ async fn stream_data(
 
     // Send packets with increasing sequence numbers until the elapsed time
     // is greater than the timeout.
-    for seqno in 1..u32::MAX {
+    for seqno in 1..TIMEOUT_SEQNO {
         interval.tick().await;
         trace!("send {}", seqno);
         sender.send(seqno).await?;
@@ -348,7 +382,7 @@ async fn stream_data(
     // Send the timeout message. Do it a bunch and hope one makes it through.
     info!("sending timeout message");
     for _ in 0..100 {
-        sender.send(u32::MAX).await?;
+        sender.send(TIMEOUT_SEQNO).await?;
     }
     Ok(())
 }
@@ -358,6 +392,10 @@ async fn main() -> io::Result<()> {
     env_logger::init();
 
     let args = Cli::parse();
+    assert!(
+        (0.0..=1.0).contains(&args.loss),
+        "ERROR: <loss> must be between 0 and 1"
+    );
     let (tx, rx) = mpsc::channel(100);
 
     let sock = {
@@ -367,7 +405,7 @@ async fn main() -> io::Result<()> {
         Arc::new(sock)
     };
     let sender = PacketSender::new(args.quack_style.is_some(), tx).await?;
-    send_data(sock.clone(), args.bytes, rx).await?;
+    send_data(sock.clone(), args.bytes, args.loss, rx).await?;
     listen_for_nacks(sock, sender.clone());
     if let Some(quack_style) = args.quack_style {
         match quack_style {
@@ -390,3 +428,72 @@ async fn main() -> io::Result<()> {
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: usize = 8;
+
+    /// Sends seqnos 1..=20 with distinct ids, dropping `dropped` before the
+    /// sidekick. Returns the sender's quACK, the sidekick's quACK, and the
+    /// logged (seqno, id) pairs.
+    fn simulate(dropped: &[u32]) -> (PowerSumQuackU32, PowerSumQuackU32, Vec<(u32, u32)>) {
+        let mut my_quack = PowerSumQuackU32::new(THRESHOLD);
+        let mut quack = PowerSumQuackU32::new(THRESHOLD);
+        let mut seqno_ids = Vec::new();
+        for seqno in 1..=20 {
+            let id = seqno * 7919 + 13;
+            my_quack.insert(id);
+            seqno_ids.push((seqno, id));
+            if !dropped.contains(&seqno) {
+                quack.insert(id);
+            }
+        }
+        (my_quack, quack, seqno_ids)
+    }
+
+    #[test]
+    fn test_nack_set_drops() {
+        let (my_quack, quack, seqno_ids) = simulate(&[3, 11]);
+        let missing = nack_set(&my_quack, quack, &seqno_ids);
+        assert_eq!(missing, vec![seqno_ids[2], seqno_ids[10]]);
+    }
+
+    #[tokio::test]
+    async fn test_send_ids() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut sender = PacketSender::new(true, tx).await.unwrap();
+        sender.send(1).await.unwrap();
+        sender.send(TIMEOUT_SEQNO).await.unwrap();
+
+        // Every packet sent, including the timeout message, is logged with
+        // the id it is sent with, since the sidekick quACKs it too.
+        let seqno_ids = sender.seqno_ids.lock().await.clone();
+        assert_eq!(seqno_ids.len(), 2);
+        assert_eq!(seqno_ids[1].0, TIMEOUT_SEQNO);
+        for &(seqno, id) in seqno_ids.iter() {
+            assert_eq!(rx.recv().await, Some((seqno, id)));
+            assert!((1..MODULUS).contains(&id));
+        }
+    }
+
+    #[test]
+    fn test_nack_set_endpoints() {
+        let seqno_ids = vec![(1, 1), (2, MODULUS - 1), (3, 5)];
+        let mut my_quack = PowerSumQuackU32::new(THRESHOLD);
+        let mut quack = PowerSumQuackU32::new(THRESHOLD);
+        for &(_, id) in seqno_ids.iter() {
+            my_quack.insert(id);
+        }
+        quack.insert(5);
+        let missing = nack_set(&my_quack, quack, &seqno_ids);
+        assert_eq!(missing, vec![(1, 1), (2, MODULUS - 1)]);
+    }
+
+    #[test]
+    fn test_nack_set_no_drops() {
+        let (my_quack, quack, seqno_ids) = simulate(&[]);
+        assert!(nack_set(&my_quack, quack, &seqno_ids).is_empty());
+    }
+}
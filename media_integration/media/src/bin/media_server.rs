@@ -1,56 +1,124 @@
 //! Receives dummy WebRTC messages on a UDP socket.
 //!
 //! The first four bytes of the payload indicate a packet sequence number.
-//! The sequence numbers start at 1.
+//! The sequence numbers start at 1. The rest of the payload is ignored, so
+//! packets may vary in size.
 //! Store the incoming packets in a buffer and play them as soon as the next
 //! packet in the sequence is available. If it ever detects a loss i.e. a
 //! packet is missing after 3 later packets have been received, send a NACK
 //! back to the sender that contains the sequence number of the missing packet.
+//! If the buffer holds more than <MAX_BUFFER> packets, the oldest missing
+//! packets are declared lost and playout skips ahead. NACKs are resent at
+//! most every RTT until the packet arrives, checked whenever a packet is
+//! received. With <IDLE_TIMEOUT>, NACKs are also resent every RTT on a timer
+//! even if no packets arrive, until no packets have been received for
+//! <IDLE_TIMEOUT>. Then the sender has likely stopped and NACKing ceases
+//! until it sends again.
 //!
 //! On receiving a timeout packet (sequence number is the max u32 integer),
-//! print packet statistics. Print the average, p95, and p99 latencies, where
-//! the latencies are how long the packet stayed in the queue. Print histogram.
+//! or on Ctrl-C, print packet statistics. Print the average, p95, and p99
+//! latencies, where the latencies are how long the packet stayed in the
+//! queue. Print histogram.
 use std::collections::VecDeque;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 
 use clap::Parser;
+#[cfg(feature = "hdr")]
+use hdrhistogram::Histogram;
 use log::{debug, trace};
 use tokio::net::UdpSocket;
-use tokio::time::{Duration, Instant};
+use tokio::time::{Duration, Instant, MissedTickBehavior};
 
 #[derive(Parser)]
 struct Cli {
     /// Port to listen on.
     #[arg(long, default_value_t = 5201)]
     port: u16,
-    /// Number of bytes to expect in the payload.
-    #[arg(long, short, default_value_t = 240)]
-    bytes: usize,
     /// End-to-end RTT in ms, which is also how often to resend NACKs.
     #[arg(long)]
     rtt: u64,
     /// Whether to loop forever.
     #[arg(long = "loop")]
     should_loop: bool,
+    /// Max number of packets to buffer, at least 1. If exceeded, the oldest
+    /// missing packets are declared lost. If missing, the buffer is unbounded.
+    #[arg(long = "max-buffer")]
+    max_buffer: Option<NonZeroUsize>,
+    /// Time in ms without receiving any packets after which the sender is
+    /// considered idle. If set, NACKs are resent every RTT on a timer until
+    /// the sender is idle, and then cease until the next packet arrives. If
+    /// missing, NACKs are only sent or resent when a packet arrives.
+    #[arg(long = "idle-timeout")]
+    idle_timeout: Option<u64>,
 }
 
+/// Sequence number of the timeout message. Data packets use sequence numbers
+/// `1..TIMEOUT_SEQNO`; sequence number 0 is reserved and ignored like any
+/// already-played packet.
 const TIMEOUT_SEQNO: u32 = u32::MAX;
 
+/// Max UDP payload size to expect.
+const MTU: usize = 1500;
+
+/// Packets just need 4 bytes for the sequence number.
+const SEQNO_SIZE: usize = 4;
+
+/// Parses the sequence number that drives the loss logic out of a packet
+/// payload, so the server can be reused for other wire formats e.g., RTP.
+trait IdentifierExtractor {
+    /// Returns the sequence number, or `None` if the payload is malformed.
+    fn extract(&self, payload: &[u8]) -> Option<u32>;
+}
+
+/// The sequence number is in the first four bytes of the payload, in
+/// network byte order.
+struct SeqnoExtractor;
+
+impl IdentifierExtractor for SeqnoExtractor {
+    fn extract(&self, payload: &[u8]) -> Option<u32> {
+        if payload.len() < SEQNO_SIZE {
+            return None;
+        }
+        Some(u32::from_be_bytes([
+            payload[0], payload[1], payload[2], payload[3],
+        ]))
+    }
+}
+
 struct Statistics {
     values: Vec<Duration>,
+    /// The same values in ns, for high-dynamic-range percentiles.
+    #[cfg(feature = "hdr")]
+    hdr: Histogram<u64>,
 }
 
 impl Statistics {
     /// Create a new histogram for adding duration values.
     fn new() -> Self {
-        Self { values: Vec::new() }
+        Self {
+            values: Vec::new(),
+            #[cfg(feature = "hdr")]
+            hdr: Histogram::new(3).unwrap(),
+        }
     }
 
     /// Add a new duration value.
     fn add_value(&mut self, value: Duration) {
         self.values.push(value);
+        #[cfg(feature = "hdr")]
+        self.hdr.record(value.as_nanos() as u64).unwrap();
+    }
+
+    /// Add all the values from another set of statistics, e.g., to compute
+    /// percentiles across multiple connections.
+    fn merge(&mut self, other: Statistics) {
+        self.values.extend(other.values);
+        #[cfg(feature = "hdr")]
+        self.hdr.add(other.hdr).unwrap();
     }
 
     /// Print average, p95, and p99 latency statistics.
@@ -59,9 +127,12 @@ impl Statistics {
         values.sort();
         let len = values.len();
         println!("Num Values: {}", len);
-        println!("Average: {:?}", values[(len as f64 * 0.50) as usize]);
-        println!("p95: {:?}", values[(len as f64 * 0.95) as usize]);
-        println!("p99: {:?}", values[(len as f64 * 0.99) as usize]);
+        if len == 0 {
+            return;
+        }
+        println!("Average: {:?}", percentile(&values, 0.50));
+        println!("p95: {:?}", percentile(&values, 0.95));
+        println!("p99: {:?}", percentile(&values, 0.99));
         let values_raw = values
             .into_iter()
             .map(|duration| duration.as_secs() * 1000000000 + duration.subsec_nanos() as u64)
@@ -86,10 +157,44 @@ impl Statistics {
     }
 
     /// Print a histogram of the latency statistics.
+    #[cfg(not(feature = "hdr"))]
     fn print_histogram(&self) {
         println!("no histogram yet");
         // unimplemented!()
     }
+
+    /// Print the HdrHistogram percentile distribution of the latency
+    /// statistics, in ns.
+    #[cfg(feature = "hdr")]
+    fn print_histogram(&self) {
+        println!("{:>12} {:>14} {:>10}", "Value", "Percentile", "TotalCount");
+        let mut total = 0;
+        for v in self.hdr.iter_quantiles(1) {
+            total += v.count_since_last_iteration();
+            println!(
+                "{:>12} {:>14.12} {:>10}",
+                v.value_iterated_to(),
+                v.quantile_iterated_to(),
+                total
+            );
+        }
+        println!(
+            "#[Mean = {:.3}, StdDeviation = {:.3}]",
+            self.hdr.mean(),
+            self.hdr.stdev()
+        );
+        println!(
+            "#[Max = {}, Total count = {}]",
+            self.hdr.max(),
+            self.hdr.len()
+        );
+    }
+}
+
+/// Returns the value at this percentile of the sorted, non-empty values.
+fn percentile(values: &[Duration], percent: f64) -> Duration {
+    let index = (values.len() as f64 * percent) as usize;
+    values[std::cmp::min(index, values.len() - 1)]
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -110,31 +215,56 @@ impl Packet {
 }
 
 struct BufferedPackets {
-    send_sock: Arc<UdpSocket>,
     nack_frequency: Duration,
+    /// Max number of packets in the buffer before the missing packets at the
+    /// front are declared lost.
+    max_depth: Option<NonZeroUsize>,
+    /// Time without receiving any packets after which NACKs cease.
+    idle_timeout: Option<Duration>,
+    /// Time the last packet was received.
+    time_last_recv: Option<Instant>,
     /// Next seqno to play, and the seqno of the first packet in the buffer
     /// if the buffer is non-empty.
     next_seqno: u32,
     buffer: VecDeque<Packet>,
+    /// Received times of packets that left the buffer early to enforce the
+    /// max depth, but have not yet been returned by `pop_seqno()`.
+    played: VecDeque<Instant>,
 }
 
 impl BufferedPackets {
-    async fn new(sock: Arc<UdpSocket>, nack_frequency: Duration) -> io::Result<Self> {
-        Ok(Self {
-            send_sock: sock,
+    fn new(
+        nack_frequency: Duration,
+        max_depth: Option<NonZeroUsize>,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
             nack_frequency,
+            max_depth,
+            idle_timeout,
+            time_last_recv: None,
             next_seqno: 1,
             buffer: VecDeque::new(),
-        })
+            played: VecDeque::new(),
+        }
     }
 
     /// Receive a packet with this sequence number.
     fn recv_seqno(&mut self, new_seqno: u32, now: Instant) {
+        self.time_last_recv = Some(now);
+
         // Ignore the seqno if it has already been received.
         if new_seqno < self.next_seqno {
             return;
         }
 
+        // Make room for the seqno before growing the buffer, so a large jump
+        // in seqnos never allocates more than the max depth.
+        if let Some(max_depth) = self.max_depth {
+            let max_depth = u32::try_from(max_depth.get()).unwrap_or(u32::MAX);
+            self.skip_to(new_seqno.saturating_sub(max_depth - 1));
+        }
+
         // Add packets to the buffer until the seqno is guaranteed to be there.
         if self.buffer.is_empty() {
             self.buffer.push_back(Packet::new(self.next_seqno));
@@ -159,9 +289,31 @@ impl BufferedPackets {
         unreachable!()
     }
 
+    /// Advance the next seqno to play to at least `min_seqno`, declaring
+    /// the missing packets before it lost. Received packets before it are
+    /// kept for `pop_seqno()`.
+    fn skip_to(&mut self, min_seqno: u32) {
+        while self.next_seqno < min_seqno {
+            if let Some(packet) = self.buffer.pop_front() {
+                if let Some(time_recv) = packet.time_recv {
+                    self.played.push_back(time_recv);
+                } else {
+                    debug!("declaring {} lost", packet.seqno);
+                }
+                self.next_seqno += 1;
+            } else {
+                debug!("declaring {}..{} lost", self.next_seqno, min_seqno);
+                self.next_seqno = min_seqno;
+            }
+        }
+    }
+
     /// Return the received time of the next packet to play if the next packet
     /// in the sequence is available. Removes that packet from the buffer.
     fn pop_seqno(&mut self) -> Option<Instant> {
+        if let Some(time_recv) = self.played.pop_front() {
+            return Some(time_recv);
+        }
         if !self.buffer.is_empty() && self.buffer.front().unwrap().time_recv.is_some() {
             self.next_seqno += 1;
             Some(self.buffer.pop_front().unwrap().time_recv.unwrap())
@@ -170,14 +322,26 @@ impl BufferedPackets {
         }
     }
 
-    /// Send NACKs to the given client address if any packets are missing i.e.,
-    /// three later packets have been received. Also resend NACKs if it has
+    /// Whether no packets have been received for longer than the idle
+    /// timeout, meaning the stream has likely ended rather than the missing
+    /// packets being lost. Resets as soon as another packet is received.
+    fn is_idle(&self, now: Instant) -> bool {
+        match (self.idle_timeout, self.time_last_recv) {
+            (Some(idle_timeout), Some(time_last_recv)) => now - time_last_recv > idle_timeout,
+            _ => false,
+        }
+    }
+
+    /// Return the sequence numbers to NACK if any packets are missing i.e.,
+    /// three later packets have been received. Also NACK again if it has
     /// been more than an RTT since the last NACK for that sequence number.
-    /// It may be considerably more than an RTT for NACK retransmissions if
-    /// this function is only called on receiving a packet.
-    async fn send_nacks(&mut self, now: Instant, nack_addr: &SocketAddr) -> io::Result<()> {
-        if self.buffer.is_empty() {
-            return Ok(());
+    /// Marks the returned packets as NACKed at `now`. Returns nothing while
+    /// the sender is idle, since it is no longer around to retransmit.
+    fn nacks(&mut self, now: Instant) -> Vec<u32> {
+        let mut seqnos = Vec::new();
+        if self.is_idle(now) {
+            trace!("sender is idle, not nacking");
+            return seqnos;
         }
         for packet in self.buffer.iter_mut() {
             if packet.time_recv.is_some() {
@@ -185,23 +349,106 @@ impl BufferedPackets {
             }
             if let Some(time_nack) = packet.time_nack.as_mut() {
                 if now - *time_nack > self.nack_frequency {
-                    let buf = packet.seqno.to_be_bytes();
-                    debug!("nacking {} (again) {:?}", packet.seqno, nack_addr);
-                    self.send_sock.send_to(&buf, nack_addr).await?;
+                    debug!("nacking {} (again)", packet.seqno);
+                    seqnos.push(packet.seqno);
                     *time_nack = now;
                 }
             } else {
-                debug!("nacking {} {:?}", packet.seqno, nack_addr);
-                let buf = packet.seqno.to_be_bytes();
+                debug!("nacking {}", packet.seqno);
+                seqnos.push(packet.seqno);
                 packet.time_nack = Some(now);
-                self.send_sock.send_to(&buf, nack_addr).await?;
-                continue;
             }
         }
+        seqnos
+    }
+
+    /// Send NACKs on the socket to the given client address for the sequence
+    /// numbers returned by `nacks()`.
+    async fn send_nacks(
+        &mut self,
+        sock: &UdpSocket,
+        now: Instant,
+        nack_addr: &SocketAddr,
+    ) -> io::Result<()> {
+        for seqno in self.nacks(now) {
+            let buf = seqno.to_be_bytes();
+            trace!("send nack {} {:?}", seqno, nack_addr);
+            sock.send_to(&buf, nack_addr).await?;
+        }
         Ok(())
     }
 }
 
+/// Why the server stopped receiving packets.
+#[derive(Debug, PartialEq, Eq)]
+enum Stop {
+    /// Received the timeout message from the client.
+    Timeout,
+    /// Interrupted by Ctrl-C, or whichever shutdown signal was given.
+    Interrupted,
+}
+
+/// Receive packets on the socket until the timeout message or until the
+/// `shutdown` future completes (Ctrl-C in main), buffering them for playout
+/// and NACKing any that are missing. If there is an idle timeout, NACKs are
+/// also resent on a timer until the sender is idle, so retransmissions are
+/// not delayed until the next packet arrives. Returns the collected statistics and why it stopped.
+async fn serve<E, S>(
+    sock: Arc<UdpSocket>,
+    nack_frequency: Duration,
+    max_buffer: Option<NonZeroUsize>,
+    idle_timeout: Option<Duration>,
+    extractor: &E,
+    shutdown: &mut S,
+) -> io::Result<(Statistics, Stop)>
+where
+    E: IdentifierExtractor,
+    S: Future<Output = io::Result<()>> + Unpin,
+{
+    let mut stats = Statistics::new();
+    let mut pkts = BufferedPackets::new(nack_frequency, max_buffer, idle_timeout);
+    let mut buf = vec![0; MTU];
+    let mut nack_addr = None;
+    let mut nack_interval = tokio::time::interval(nack_frequency.max(Duration::from_millis(1)));
+    nack_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    debug!("webrtc server is now listening");
+    loop {
+        let (len, addr) = tokio::select! {
+            res = sock.recv_from(&mut buf) => res?,
+            _ = nack_interval.tick(), if idle_timeout.is_some() => {
+                if let Some(addr) = nack_addr {
+                    pkts.send_nacks(&sock, Instant::now(), &addr).await?;
+                }
+                continue;
+            }
+            res = &mut *shutdown => {
+                res?;
+                debug!("interrupted");
+                return Ok((stats, Stop::Interrupted));
+            }
+        };
+        let seqno = match extractor.extract(&buf[..len]) {
+            Some(seqno) => seqno,
+            None => {
+                debug!("dropping malformed packet ({} bytes)", len);
+                continue;
+            }
+        };
+        trace!("received seqno {} ({} bytes)", seqno, len);
+        if seqno == TIMEOUT_SEQNO {
+            debug!("timeout message received");
+            return Ok((stats, Stop::Timeout));
+        }
+        let now = Instant::now();
+        nack_addr = Some(addr);
+        pkts.recv_seqno(seqno, now);
+        while let Some(time_recv) = pkts.pop_seqno() {
+            stats.add_value(now - time_recv);
+        }
+        pkts.send_nacks(&sock, now, &addr).await?;
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> io::Result<()> {
     env_logger::init();
@@ -215,40 +462,363 @@ async fn main() -> io::Result<()> {
         let sock = UdpSocket::bind(addr).await.unwrap();
         Arc::new(sock)
     };
+    let mut buf = vec![0; MTU];
+    let mut total_stats = Statistics::new();
+    let mut num_connections = 0;
+
+    // Listen for Ctrl-C for the lifetime of the server, so an interrupt that
+    // arrives between connections is still seen.
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
     loop {
-        let mut stats = Statistics::new();
-        let mut pkts = BufferedPackets::new(sock.clone(), nack_frequency).await?;
-        let mut buf = vec![0; args.bytes];
-        debug!("webrtc server is now listening");
-        loop {
-            let (len, addr) = sock.recv_from(&mut buf).await?;
-            assert_eq!(len, args.bytes);
-            let seqno = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
-            trace!("received seqno {} ({} bytes)", seqno, len);
-            if seqno == TIMEOUT_SEQNO {
-                debug!("timeout message received");
-                break;
-            }
-            let now = Instant::now();
-            pkts.recv_seqno(seqno, now);
-            while let Some(time_recv) = pkts.pop_seqno() {
-                stats.add_value(now - time_recv);
-            }
-            pkts.send_nacks(now, &addr).await?;
-        }
+        let (stats, stop) = serve(
+            sock.clone(),
+            nack_frequency,
+            args.max_buffer,
+            args.idle_timeout.map(Duration::from_millis),
+            &SeqnoExtractor,
+            &mut ctrl_c,
+        )
+        .await?;
 
         // Print statistics before exiting.
         stats.print_statistics();
         stats.print_histogram();
+        total_stats.merge(stats);
+        num_connections += 1;
 
-        // Exit the loop if not set.
-        if !args.should_loop {
+        // Exit the loop if not set or if interrupted.
+        if !args.should_loop || stop == Stop::Interrupted {
             break;
         }
 
         // Process remaining timeout messages.
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            res = &mut ctrl_c => {
+                res?;
+                debug!("interrupted");
+                break;
+            }
+        }
         while sock.try_recv(&mut buf).is_ok() {}
     }
+
+    // Print aggregate statistics across all connections.
+    if num_connections > 1 {
+        println!("Aggregate over {} connections", num_connections);
+        total_stats.print_statistics();
+        total_stats.print_histogram();
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send the payloads to `serve` on a loopback socket, then return its
+    /// statistics once it stops. The last payload should be a timeout.
+    async fn serve_payloads<E: IdentifierExtractor>(
+        extractor: &E,
+        payloads: &[Vec<u8>],
+    ) -> (Statistics, Stop) {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = sock.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for payload in payloads {
+            client.send_to(payload, addr).await.unwrap();
+        }
+        let mut shutdown = std::future::pending();
+        serve(
+            sock,
+            Duration::from_millis(100),
+            None,
+            None,
+            extractor,
+            &mut shutdown,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// A payload of `len` bytes starting with the big-endian seqno.
+    fn payload(seqno: u32, len: usize) -> Vec<u8> {
+        let mut payload = vec![0xFF; len];
+        payload[..SEQNO_SIZE].copy_from_slice(&seqno.to_be_bytes());
+        payload
+    }
+
+    #[test]
+    fn test_first_nack() {
+        let mut pkts = BufferedPackets::new(Duration::from_millis(100), None, None);
+        let now = Instant::now();
+        assert!(pkts.nacks(now).is_empty());
+        pkts.recv_seqno(1, now);
+        assert!(pkts.nacks(now).is_empty());
+        pkts.recv_seqno(3, now);
+        pkts.recv_seqno(5, now);
+        assert_eq!(pkts.nacks(now), vec![2, 4]);
+        assert!(pkts.nacks(now).is_empty());
+    }
+
+    #[test]
+    fn test_renack_after_nack_frequency() {
+        let mut pkts = BufferedPackets::new(Duration::from_millis(100), None, None);
+        let start = Instant::now();
+        pkts.recv_seqno(2, start);
+        assert_eq!(pkts.nacks(start), vec![1]);
+        assert!(pkts.nacks(start + Duration::from_millis(50)).is_empty());
+        assert!(pkts.nacks(start + Duration::from_millis(100)).is_empty());
+        assert_eq!(pkts.nacks(start + Duration::from_millis(101)), vec![1]);
+        assert!(pkts.nacks(start + Duration::from_millis(150)).is_empty());
+
+        // No more NACKs once the packet arrives.
+        pkts.recv_seqno(1, start + Duration::from_millis(160));
+        assert!(pkts.nacks(start + Duration::from_millis(500)).is_empty());
+    }
+
+    #[test]
+    fn test_idle_sender_stops_mid_stream() {
+        let mut pkts = BufferedPackets::new(
+            Duration::from_millis(100),
+            None,
+            Some(Duration::from_millis(300)),
+        );
+        let t0 = Instant::now();
+        for seqno in [1, 3, 5] {
+            pkts.recv_seqno(seqno, t0);
+        }
+        assert_eq!(pkts.nacks(t0), vec![2, 4]);
+        assert_eq!(pkts.nacks(t0 + Duration::from_millis(150)), vec![2, 4]);
+
+        // The sender stops: NACKing ceases after the idle period.
+        assert!(pkts.is_idle(t0 + Duration::from_millis(400)));
+        assert!(pkts.nacks(t0 + Duration::from_millis(400)).is_empty());
+        assert!(pkts.nacks(t0 + Duration::from_millis(550)).is_empty());
+
+        // The sender resumes, and both gaps are NACKed again.
+        let t1 = t0 + Duration::from_millis(700);
+        pkts.recv_seqno(6, t1);
+        assert!(!pkts.is_idle(t1));
+        assert_eq!(pkts.nacks(t1), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_max_buffer_rejects_zero() {
+        assert!(
+            Cli::try_parse_from(["media_server", "--rtt", "100", "--max-buffer", "0"]).is_err()
+        );
+        let args = Cli::try_parse_from(["media_server", "--rtt", "100", "--max-buffer", "8"]);
+        assert_eq!(args.unwrap().max_buffer, NonZeroUsize::new(8));
+    }
+
+    #[test]
+    fn test_max_depth_sustained_loss() {
+        let max_depth = 8;
+        let mut pkts = BufferedPackets::new(
+            Duration::from_millis(100),
+            NonZeroUsize::new(max_depth),
+            None,
+        );
+        let now = Instant::now();
+        let mut played = 0;
+        for seqno in (1..1000).step_by(3) {
+            pkts.recv_seqno(seqno, now);
+            assert!(pkts.buffer.len() <= max_depth);
+            while pkts.pop_seqno().is_some() {
+                played += 1;
+            }
+        }
+        // Every received packet is either played exactly once or still
+        // waiting behind a missing packet within the max depth.
+        let buffered = pkts.buffer.iter().filter(|p| p.time_recv.is_some());
+        assert_eq!(played + buffered.count(), (1..1000).step_by(3).count());
+    }
+
+    #[test]
+    fn test_max_depth_seqno_jump() {
+        let max_depth = 8;
+        let mut pkts = BufferedPackets::new(
+            Duration::from_millis(100),
+            NonZeroUsize::new(max_depth),
+            None,
+        );
+        let now = Instant::now();
+        pkts.recv_seqno(1, now);
+        pkts.recv_seqno(3, now);
+        pkts.recv_seqno(1 << 31, now);
+        assert!(pkts.buffer.len() <= max_depth);
+        assert_eq!(pkts.next_seqno, (1 << 31) - max_depth as u32 + 1);
+
+        // The packets received before the jump are played first.
+        assert_eq!(pkts.pop_seqno(), Some(now));
+        assert_eq!(pkts.pop_seqno(), Some(now));
+        assert_eq!(pkts.pop_seqno(), None);
+        pkts.recv_seqno((1 << 31) - 1, now);
+        assert_eq!(pkts.nacks(now).len(), max_depth - 2);
+    }
+
+    #[test]
+    fn test_seqno_extractor_sizes() {
+        for len in [4, 5, 240, MTU] {
+            assert_eq!(SeqnoExtractor.extract(&payload(7, len)), Some(7));
+        }
+        assert_eq!(SeqnoExtractor.extract(&[]), None);
+        assert_eq!(SeqnoExtractor.extract(&[0, 0, 7]), None);
+    }
+
+    /// Parses the 16-bit sequence number at bytes 2..4 of an RTP header.
+    struct RtpExtractor;
+
+    impl IdentifierExtractor for RtpExtractor {
+        fn extract(&self, payload: &[u8]) -> Option<u32> {
+            if payload.len() < 12 {
+                return None;
+            }
+            Some(u16::from_be_bytes([payload[2], payload[3]]).into())
+        }
+    }
+
+    /// A 12-byte RTP header with the sequence number, followed by a payload
+    /// whose first four bytes would parse as a different `SeqnoExtractor`
+    /// sequence number.
+    fn rtp_packet(seqno: u16) -> Vec<u8> {
+        let mut packet = vec![0x80, 0x60];
+        packet.extend_from_slice(&seqno.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&[0xAB; 160]);
+        packet
+    }
+
+    #[test]
+    fn test_rtp_extractor() {
+        assert_eq!(RtpExtractor.extract(&rtp_packet(42)), Some(42));
+        assert_eq!(RtpExtractor.extract(&rtp_packet(42)[..11]), None);
+    }
+
+    /// A 16-bit RTP seqno can't reach `TIMEOUT_SEQNO`, so map the max RTP
+    /// seqno to the timeout message to stop the server.
+    struct TimeoutRtpExtractor;
+
+    impl IdentifierExtractor for TimeoutRtpExtractor {
+        fn extract(&self, payload: &[u8]) -> Option<u32> {
+            match RtpExtractor.extract(payload)? {
+                0xFFFF => Some(TIMEOUT_SEQNO),
+                seqno => Some(seqno),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_custom_extractor() {
+        let payloads = vec![
+            rtp_packet(1),
+            rtp_packet(3),
+            rtp_packet(2),
+            rtp_packet(0xFFFF),
+        ];
+        let (stats, stop) = serve_payloads(&TimeoutRtpExtractor, &payloads).await;
+        assert_eq!(stop, Stop::Timeout);
+        assert_eq!(stats.values.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_serve_shutdown() {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = sock.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for seqno in [1, 2, 4] {
+            client.send_to(&payload(seqno, 240), addr).await.unwrap();
+        }
+
+        // Shut down before the timeout message, keeping the packets played
+        // so far.
+        let mut shutdown = Box::pin(async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(())
+        });
+        let (stats, stop) = serve(
+            sock,
+            Duration::from_millis(100),
+            None,
+            None,
+            &SeqnoExtractor,
+            &mut shutdown,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stop, Stop::Interrupted);
+        assert_eq!(stats.values.len(), 2);
+        stats.print_statistics();
+        stats.print_histogram();
+    }
+
+    #[cfg(feature = "hdr")]
+    #[test]
+    fn test_hdr_p99() {
+        let mut stats = Statistics::new();
+        for ms in 1..=100 {
+            stats.add_value(Duration::from_millis(ms));
+        }
+        assert_eq!(stats.hdr.len(), 100);
+        let p99 = stats.hdr.value_at_quantile(0.99);
+        assert!(stats.hdr.equivalent(p99, 99_000_000));
+        stats.print_histogram();
+    }
+
+    /// Statistics for two connections with 1..=50 ms and 51..=100 ms.
+    fn merged_statistics() -> Statistics {
+        let mut stats = Statistics::new();
+        let mut other = Statistics::new();
+        for ms in 1..=50 {
+            stats.add_value(Duration::from_millis(ms));
+            other.add_value(Duration::from_millis(ms + 50));
+        }
+        stats.merge(other);
+        stats
+    }
+
+    #[test]
+    fn test_merge_percentiles() {
+        let stats = merged_statistics();
+        let mut values = stats.values.clone();
+        values.sort();
+        assert_eq!(values.len(), 100);
+        assert_eq!(percentile(&values, 0.95), Duration::from_millis(96));
+        assert_eq!(percentile(&values, 0.99), Duration::from_millis(100));
+        stats.print_statistics();
+    }
+
+    #[cfg(feature = "hdr")]
+    #[test]
+    fn test_merge_hdr() {
+        let stats = merged_statistics();
+        assert_eq!(stats.hdr.len(), 100);
+        let p95 = stats.hdr.value_at_quantile(0.95);
+        let p99 = stats.hdr.value_at_quantile(0.99);
+        assert!(stats.hdr.equivalent(p95, 95_000_000));
+        assert!(stats.hdr.equivalent(p99, 99_000_000));
+    }
+
+    #[test]
+    fn test_print_empty_statistics() {
+        let stats = Statistics::new();
+        stats.print_statistics();
+        stats.print_histogram();
+    }
+
+    #[tokio::test]
+    async fn test_serve_variable_sizes() {
+        let payloads = vec![
+            payload(1, 240),
+            payload(2, 4),
+            vec![0, 0],
+            payload(3, 1200),
+            payload(TIMEOUT_SEQNO, 100),
+        ];
+        let (stats, stop) = serve_payloads(&SeqnoExtractor, &payloads).await;
+        assert_eq!(stop, Stop::Timeout);
+        assert_eq!(stats.values.len(), 3);
+    }
+}
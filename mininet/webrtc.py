@@ -10,7 +10,6 @@ from mininet.log import setLogLevel
 
 def start_media_server(net, args, env):
     cmd = './target/release/media_server --port 5123 '
-    cmd += f'-b {args.client_bytes} '
     cmd += f'--rtt {2 * (args.delay1 + args.delay2)} '
     print(cmd)
     cmd = cmd.strip().split(' ')
@@ -6,6 +6,16 @@ use libc::c_uchar;
 pub const ID_OFFSET: usize = 63;
 pub const BUFFER_SIZE: usize = ID_OFFSET + 4;
 
+/// Reads the sidekick identifier from the first 4 bytes of `raw` in network
+/// byte order, so hosts with different native endianness derive the same
+/// identifier from the same packet. Both the quACK sender and receiver must
+/// use this to convert packet bytes. Returns None if `raw` is shorter than
+/// 4 bytes.
+pub fn canonicalize_identifier(raw: &[u8]) -> Option<u32> {
+    let bytes: [u8; 4] = raw.get(..4)?.try_into().ok()?;
+    Some(u32::from_be_bytes(bytes))
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
@@ -59,12 +69,7 @@ impl UdpParser {
         let dst_ip = format!("{}.{}.{}.{}", x[30], x[31], x[32], x[33]);
         let src_port = u16::from_be_bytes([x[34], x[35]]);
         let dst_port = u16::from_be_bytes([x[36], x[37]]);
-        let identifier = u32::from_be_bytes([
-            x[ID_OFFSET],
-            x[ID_OFFSET + 1],
-            x[ID_OFFSET + 2],
-            x[ID_OFFSET + 3],
-        ]);
+        let identifier = Self::parse_identifier(x);
         Some(UdpParser {
             src_mac,
             dst_mac,
@@ -94,14 +99,42 @@ impl UdpParser {
         ]
     }
 
-    /// Returns the sidekick identifier assuming the buffer represents
-    /// a QUIC UDP packet.
+    /// Returns the sidekick identifier at `ID_OFFSET` assuming the buffer
+    /// represents a QUIC UDP packet. The buffer always holds the 4 bytes
+    /// after `ID_OFFSET`, so this cannot fail.
     pub fn parse_identifier(x: &[u8; BUFFER_SIZE]) -> u32 {
-        u32::from_be_bytes([
-            x[ID_OFFSET],
-            x[ID_OFFSET + 1],
-            x[ID_OFFSET + 2],
-            x[ID_OFFSET + 3],
-        ])
+        canonicalize_identifier(&x[ID_OFFSET..]).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonicalize_identifier_assembly() {
+        let raw = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(canonicalize_identifier(&raw), Some(0x1234_5678));
+
+        // Network byte order regardless of the host's native endianness.
+        if cfg!(target_endian = "little") {
+            assert_ne!(canonicalize_identifier(&raw), Some(u32::from_ne_bytes(raw)));
+        }
+
+        // Trailing bytes are ignored.
+        let mut bytes = raw.to_vec();
+        bytes.extend_from_slice(&[0xff; 8]);
+        assert_eq!(canonicalize_identifier(&bytes), Some(0x1234_5678));
+
+        // The same bytes at the identifier offset of a captured packet.
+        let mut buf = [0; BUFFER_SIZE];
+        buf[ID_OFFSET..].copy_from_slice(&raw);
+        assert_eq!(UdpParser::parse_identifier(&buf), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_canonicalize_identifier_short() {
+        assert_eq!(canonicalize_identifier(&[]), None);
+        assert_eq!(canonicalize_identifier(&[0x12, 0x34, 0x56]), None);
     }
 }
@@ -2,7 +2,7 @@ pub mod buffer;
 mod sidekick;
 pub mod sidekick_multi;
 
-pub use buffer::ID_OFFSET;
+pub use buffer::{canonicalize_identifier, ID_OFFSET};
 pub use sidekick::Sidekick;
 pub use sidekick_multi::SidekickMulti;
 